@@ -1,12 +1,16 @@
-use serde::de::{self, Deserialize, Deserializer, Error, SeqAccess, Visitor};
+use serde::de::{self, Deserialize, DeserializeOwned, Deserializer, Error, SeqAccess, Visitor};
 use serde::ser::{Serialize, SerializeTuple, Serializer};
 use std::clone::Clone;
 use std::cmp::Eq;
+use std::collections::hash_map::DefaultHasher;
 use std::default::Default;
 use std::fmt;
 use std::fmt::Debug;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::iter::FromIterator;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 type HashSet<K> = linked_hash_map::LinkedHashMap<K, (), ahash::RandomState>;
@@ -16,9 +20,34 @@ pub type Level = ntex_mqtt::TopicLevel;
 pub type Topic = ntex_mqtt::Topic;
 pub type TopicTree<V> = Node<V>;
 
+/// The group id of a `$share/{group}/{filter}` subscription.
+pub type SharedGroup = String;
+
+/// Prefix marking a shared subscription filter, e.g. `$share/g1/iot/+/temp`.
+const SHARED_PREFIX: &str = "$share";
+
+/// Process-wide tie-breaker mixed into [`SelectStrategy::Random`] so rapid-fire calls within
+/// the same clock tick don't all pick the same group member.
+static RANDOM_SALT: AtomicUsize = AtomicUsize::new(0);
+
+/// How a single member is chosen out of a shared-subscription group when matching.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectStrategy {
+    /// Cycle through the group's members in insertion order, one per match.
+    RoundRobin,
+    /// Pick a member at random for every match.
+    Random,
+    /// Pick the member at `hash(publish topic) % group.len()`, stable for a given topic.
+    Hash,
+}
+
 pub struct Node<V> {
     values: HashSet<V>,
     branches: HashMap<Level, Node<V>>,
+    //Shared-subscription ($share/{group}/...) members, keyed by group id.
+    groups: HashMap<SharedGroup, HashSet<V>>,
+    //Per-group round-robin cursor, created with its group and dropped when the group empties.
+    cursors: HashMap<SharedGroup, AtomicUsize>,
 }
 
 impl<V> Default for Node<V>
@@ -27,7 +56,12 @@ where
 {
     #[inline]
     fn default() -> Node<V> {
-        Self { values: HashSet::default(), branches: HashMap::default() }
+        Self {
+            values: HashSet::default(),
+            branches: HashMap::default(),
+            groups: HashMap::default(),
+            cursors: HashMap::default(),
+        }
     }
 }
 
@@ -37,6 +71,11 @@ where
 {
     #[inline]
     pub fn insert(&mut self, topic_filter: &Topic, value: V) -> bool {
+        if let Some((group, real_path)) = Self::split_shared(topic_filter.levels()) {
+            let mut path = real_path;
+            path.reverse();
+            return self._insert_shared(path, group, value);
+        }
         let mut path = topic_filter.levels().clone();
         path.reverse();
         self._insert(path, value)
@@ -51,8 +90,32 @@ where
         }
     }
 
+    #[inline]
+    fn _insert_shared(&mut self, mut path: Vec<Level>, group: SharedGroup, value: V) -> bool {
+        if let Some(first) = path.pop() {
+            self.branches.entry(first).or_default()._insert_shared(path, group, value)
+        } else {
+            self.cursors.entry(group.clone()).or_insert_with(|| AtomicUsize::new(0));
+            self.groups.entry(group).or_default().insert(value, ()).is_none()
+        }
+    }
+
+    //Splits a `$share/{group}/{filter}` filter into its group id and real filter levels.
+    #[inline]
+    fn split_shared(levels: &[Level]) -> Option<(SharedGroup, Vec<Level>)> {
+        let first = levels.first()?;
+        if first.to_string() != SHARED_PREFIX {
+            return None;
+        }
+        let group = levels.get(1)?.to_string();
+        Some((group, levels[2..].to_vec()))
+    }
+
     #[inline]
     pub fn remove(&mut self, topic_filter: &Topic, value: &V) -> bool {
+        if let Some((group, real_path)) = Self::split_shared(topic_filter.levels()) {
+            return self._remove_shared(&real_path, &group, value);
+        }
         self._remove(topic_filter.levels().as_ref(), value)
     }
 
@@ -64,7 +127,30 @@ where
             let t = &path[0];
             if let Some(x) = self.branches.get_mut(t) {
                 let res = x._remove(&path[1..], value);
-                if x.values.is_empty() && x.branches.is_empty() {
+                if x.values.is_empty() && x.branches.is_empty() && x.groups.is_empty() {
+                    self.branches.remove(t);
+                }
+                res
+            } else {
+                false
+            }
+        }
+    }
+
+    #[inline]
+    fn _remove_shared(&mut self, path: &[Level], group: &str, value: &V) -> bool {
+        if path.is_empty() {
+            let removed = self.groups.get_mut(group).map(|vs| vs.remove(value).is_some()).unwrap_or(false);
+            if self.groups.get(group).map(|vs| vs.is_empty()).unwrap_or(false) {
+                self.groups.remove(group);
+                self.cursors.remove(group);
+            }
+            removed
+        } else {
+            let t = &path[0];
+            if let Some(x) = self.branches.get_mut(t) {
+                let res = x._remove_shared(&path[1..], group, value);
+                if x.values.is_empty() && x.branches.is_empty() && x.groups.is_empty() {
                     self.branches.remove(t);
                 }
                 res
@@ -145,10 +231,128 @@ where
         }
     }
 
+    /// Like [`old_matches`](Self::old_matches), but every `$share/{group}/...` bucket
+    /// encountered along the way is collapsed to a single chosen member per `strategy`,
+    /// while ordinary (non-shared) subscribers are still all returned.
+    #[inline]
+    pub fn matches_shared(&self, topic: &Topic, strategy: SelectStrategy) -> HashMap<Topic, Vec<V>> {
+        let mut out = HashMap::default();
+        self._matches_shared(topic, topic.levels(), Vec::new(), strategy, &mut out);
+        out
+    }
+
+    #[inline]
+    fn _matches_shared(
+        &self,
+        topic: &Topic,
+        path: &[Level],
+        mut sub_path: Vec<Level>,
+        strategy: SelectStrategy,
+        out: &mut HashMap<Topic, Vec<V>>,
+    ) {
+        let mut add_to_out = |levels: Vec<Level>, node: &Self| {
+            let mut vs: Vec<V> = node.values.iter().map(|(v, _)| (*v).clone()).collect();
+            vs.extend(node.select_group_members(topic, strategy));
+            if !vs.is_empty() {
+                out.entry(Topic::from(levels)).or_default().extend(vs);
+            }
+        };
+
+        if path.is_empty() {
+            //Match parent #
+            if let Some(n) = self.branches.get(&Level::MultiWildcard) {
+                if !n.values.is_empty() || !n.groups.is_empty() {
+                    let mut sub_path = sub_path.clone();
+                    sub_path.push(Level::MultiWildcard);
+                    add_to_out(sub_path, n);
+                }
+            }
+            add_to_out(sub_path, self);
+        } else {
+            //Topic names starting with the $character cannot be matched with topic
+            //filters starting with wildcards (# or +)
+            if !(sub_path.is_empty()
+                && !matches!(path[0], Level::Blank)
+                && path[0].is_metadata()
+                && (self.branches.contains_key(&Level::MultiWildcard)
+                    || self.branches.contains_key(&Level::SingleWildcard)))
+            {
+                //Multilayer matching
+                if let Some(n) = self.branches.get(&Level::MultiWildcard) {
+                    if !n.values.is_empty() || !n.groups.is_empty() {
+                        let mut sub_path = sub_path.clone();
+                        sub_path.push(Level::MultiWildcard);
+                        add_to_out(sub_path, n);
+                    }
+                }
+
+                //Single layer matching
+                if let Some(n) = self.branches.get(&Level::SingleWildcard) {
+                    let mut sub_path = sub_path.clone();
+                    sub_path.push(Level::SingleWildcard);
+                    n._matches_shared(topic, &path[1..], sub_path, strategy, out);
+                }
+            }
+
+            //Precise matching
+            if let Some(n) = self.branches.get(&path[0]) {
+                sub_path.push(path[0].clone());
+                n._matches_shared(topic, &path[1..], sub_path, strategy, out);
+            }
+        }
+    }
+
+    //Picks exactly one member per shared group on this node, per `strategy`.
+    #[inline]
+    fn select_group_members(&self, topic: &Topic, strategy: SelectStrategy) -> Vec<V> {
+        self.groups
+            .iter()
+            .filter_map(|(group, members)| {
+                let members = members.iter().map(|(v, _)| v).collect::<Vec<&V>>();
+                if members.is_empty() {
+                    return None;
+                }
+                let idx = match strategy {
+                    SelectStrategy::RoundRobin => {
+                        let cursor = self.cursors.get(group)?;
+                        cursor.fetch_add(1, Ordering::Relaxed) % members.len()
+                    }
+                    SelectStrategy::Random => {
+                        //Not a cryptographic RNG: mixes the current time with a process-wide
+                        //counter so back-to-back calls within the same clock tick don't all land
+                        //on the same member, without pulling in a `rand` dependency.
+                        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+                        let salt = RANDOM_SALT.fetch_add(1, Ordering::Relaxed);
+                        let mut hasher = DefaultHasher::new();
+                        nanos.hash(&mut hasher);
+                        salt.hash(&mut hasher);
+                        (hasher.finish() as usize) % members.len()
+                    }
+                    SelectStrategy::Hash => {
+                        let mut hasher = DefaultHasher::new();
+                        topic.to_string().hash(&mut hasher);
+                        (hasher.finish() as usize) % members.len()
+                    }
+                };
+                members.get(idx).map(|v| (*v).clone())
+            })
+            .collect()
+    }
+
+    /// The inverse of [`matches`](Self::matches): given a subscription `filter` (which may
+    /// itself contain `+`/`#`), walk `self` as a tree of concrete topic names and lazily
+    /// yield every stored `(topic, value)` the filter selects. Used to deliver retained
+    /// messages to a new subscriber without materializing the whole store up front.
+    #[inline]
+    pub fn matches_filter<'a>(&'a self, filter: &'a Topic) -> FilterMatchedIter<'a, V> {
+        FilterMatchedIter::new(self, filter.levels(), Vec::new())
+    }
+
     #[inline]
     pub fn values_size(&self) -> usize {
+        let group_len: usize = self.groups.iter().map(|(_, vs)| vs.len()).sum();
         let len: usize = self.branches.iter().map(|(_, n)| n.values_size()).sum();
-        self.values.len() + len
+        self.values.len() + group_len + len
     }
 
     #[inline]
@@ -208,32 +412,44 @@ where
     }
 }
 
-use crate::NodeId;
-impl Serialize for Node<NodeId> {
+impl<V> Serialize for Node<V>
+where
+    V: Serialize + Hash + Eq + Clone + Debug,
+{
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
+        //2-tuple: (values, branches). `groups`/`cursors` deliberately don't travel through
+        //this impl - it's what `bincode::serialize(&topics)` for a `Node<NodeId>` has always
+        //produced on the wire, and changing its shape would silently break reading any tree
+        //persisted before $share support existed. `snapshot_cbor`/`from_cbor` below are a
+        //brand new format with no such legacy data, so group membership rides along there
+        //instead.
         let mut s = serializer.serialize_tuple(2)?;
-        s.serialize_element(&self.values.iter().collect::<Vec<(&NodeId, &())>>())?;
-        s.serialize_element(
-            &self.branches.iter().map(|(k, v)| (k, v)).collect::<Vec<(&Level, &Node<NodeId>)>>(),
-        )?;
+        s.serialize_element(&self.values.iter().collect::<Vec<(&V, &())>>())?;
+        s.serialize_element(&self.branches.iter().map(|(k, v)| (k, v)).collect::<Vec<(&Level, &Node<V>)>>())?;
         s.end()
     }
 }
 
-impl<'de> Deserialize<'de> for Node<NodeId> {
+impl<'de, V> Deserialize<'de> for Node<V>
+where
+    V: DeserializeOwned + Hash + Eq + Clone + Debug,
+{
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        struct NodeVisitor;
+        struct NodeVisitor<V>(std::marker::PhantomData<V>);
 
-        impl<'de> Visitor<'de> for NodeVisitor {
-            type Value = Node<NodeId>;
+        impl<'de, V> Visitor<'de> for NodeVisitor<V>
+        where
+            V: DeserializeOwned + Hash + Eq + Clone + Debug,
+        {
+            type Value = Node<V>;
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("struct Node<NodeId>")
+                formatter.write_str("struct Node<V>")
             }
 
             fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
@@ -245,18 +461,143 @@ impl<'de> Deserialize<'de> for Node<NodeId> {
                 }
 
                 let values = seq
-                    .next_element::<Vec<(NodeId, ())>>()?
+                    .next_element::<Vec<(V, ())>>()?
                     .ok_or_else(|| de::Error::missing_field("values"))?;
 
                 let values = HashSet::from_iter(values);
                 let branches = seq
-                    .next_element::<HashMap<Level, Node<NodeId>>>()?
+                    .next_element::<HashMap<Level, Node<V>>>()?
                     .ok_or_else(|| de::Error::missing_field("branches"))?;
 
-                Ok(Node { values, branches })
+                Ok(Node { values, branches, groups: HashMap::default(), cursors: HashMap::default() })
             }
         }
-        deserializer.deserialize_tuple(2, NodeVisitor)
+        deserializer.deserialize_tuple(2, NodeVisitor(std::marker::PhantomData))
+    }
+}
+
+/// Recursive snapshot shape used only by [`Node::snapshot_cbor`]/[`Node::from_cbor`]. The
+/// `Serialize`/`Deserialize` impls above keep the original 2-tuple `(values, branches)` wire
+/// shape for `bincode` compatibility, so `$share` group membership never travels through
+/// them. CBOR snapshots are a brand new feature with no existing persisted data to stay
+/// compatible with, so this separate 3-tuple shape is free to carry `groups` as well.
+struct CborNode<V> {
+    values: Vec<(V, ())>,
+    branches: Vec<(Level, CborNode<V>)>,
+    groups: Vec<(SharedGroup, Vec<(V, ())>)>,
+}
+
+impl<V> Serialize for CborNode<V>
+where
+    V: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_tuple(3)?;
+        s.serialize_element(&self.values)?;
+        s.serialize_element(&self.branches)?;
+        s.serialize_element(&self.groups)?;
+        s.end()
+    }
+}
+
+impl<'de, V> Deserialize<'de> for CborNode<V>
+where
+    V: DeserializeOwned,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct CborNodeVisitor<V>(std::marker::PhantomData<V>);
+
+        impl<'de, V> Visitor<'de> for CborNodeVisitor<V>
+        where
+            V: DeserializeOwned,
+        {
+            type Value = CborNode<V>;
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("struct CborNode<V>")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                if seq.size_hint() != Some(3) {
+                    return Err(Error::invalid_type(serde::de::Unexpected::Seq, &self));
+                }
+
+                let values =
+                    seq.next_element::<Vec<(V, ())>>()?.ok_or_else(|| de::Error::missing_field("values"))?;
+                let branches = seq
+                    .next_element::<Vec<(Level, CborNode<V>)>>()?
+                    .ok_or_else(|| de::Error::missing_field("branches"))?;
+                let groups = seq
+                    .next_element::<Vec<(SharedGroup, Vec<(V, ())>)>>()?
+                    .ok_or_else(|| de::Error::missing_field("groups"))?;
+
+                Ok(CborNode { values, branches, groups })
+            }
+        }
+        deserializer.deserialize_tuple(3, CborNodeVisitor(std::marker::PhantomData))
+    }
+}
+
+impl<V> Node<V>
+where
+    V: Serialize + DeserializeOwned + Hash + Eq + Clone + Debug,
+{
+    fn to_cbor_node(&self) -> CborNode<V> {
+        CborNode {
+            values: self.values.iter().map(|(v, _)| (v.clone(), ())).collect(),
+            branches: self.branches.iter().map(|(l, n)| (l.clone(), n.to_cbor_node())).collect(),
+            groups: self
+                .groups
+                .iter()
+                .map(|(g, vs)| (g.clone(), vs.iter().map(|(v, _)| (v.clone(), ())).collect()))
+                .collect(),
+        }
+    }
+
+    /// Serializes this node and its whole subtree to a compact, self-describing CBOR
+    /// buffer. Unlike `bincode`, CBOR doesn't require both ends to agree on the exact same
+    /// encoder version, which makes it a better fit for shipping tree state between cluster
+    /// members running different builds. Unlike the `Serialize` impl above, this also
+    /// carries `$share` group membership - see [`CborNode`].
+    ///
+    /// Requires `serde_cbor` as a dependency of this crate (see `Cargo.toml`).
+    pub fn snapshot_cbor(&self) -> Vec<u8> {
+        serde_cbor::to_vec(&self.to_cbor_node()).expect("serialize Node to CBOR")
+    }
+
+    /// The inverse of [`snapshot_cbor`](Self::snapshot_cbor).
+    pub fn from_cbor(bytes: &[u8]) -> serde_cbor::Result<Self> {
+        let cbor_node: CborNode<V> = serde_cbor::from_slice(bytes)?;
+        Ok(cbor_node.into_node())
+    }
+}
+
+impl<V> CborNode<V>
+where
+    V: Hash + Eq + Clone,
+{
+    fn into_node(self) -> Node<V> {
+        let values = HashSet::from_iter(self.values);
+        let branches = self.branches.into_iter().map(|(l, n)| (l, n.into_node())).collect();
+        let mut cursors: HashMap<SharedGroup, AtomicUsize> = HashMap::default();
+        let groups: HashMap<SharedGroup, HashSet<V>> = self
+            .groups
+            .into_iter()
+            .map(|(group, members)| {
+                cursors.insert(group.clone(), AtomicUsize::new(0));
+                (group, HashSet::from_iter(members))
+            })
+            .collect();
+
+        Node { values, branches, groups, cursors }
     }
 }
 
@@ -438,10 +779,504 @@ where
     }
 }
 
+/// DFS over an entire subtree (a node plus every descendant), used by [`FilterMatchedIter`]
+/// to realize a trailing `#` in a reverse-matching filter. Explores one node at a time so a
+/// huge subtree is only ever walked as far as the caller actually consumes.
+struct SubtreeIter<'a, V> {
+    //(sub_path so far, node to expand, whether to skip $-prefixed children of that node)
+    stack: Vec<(Vec<&'a Level>, &'a Node<V>, bool)>,
+    buf: std::collections::VecDeque<(Vec<&'a Level>, &'a V)>,
+}
+
+impl<'a, V> SubtreeIter<'a, V>
+where
+    V: Hash + Eq + Clone + Debug,
+{
+    #[inline]
+    fn new(node: &'a Node<V>, sub_path: Vec<&'a Level>, exclude_metadata_children: bool) -> Self {
+        Self { stack: vec![(sub_path, node, exclude_metadata_children)], buf: Default::default() }
+    }
+}
+
+impl<'a, V> Iterator for SubtreeIter<'a, V>
+where
+    V: Hash + Eq + Clone + Debug,
+{
+    type Item = (Vec<&'a Level>, &'a V);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.buf.pop_front() {
+                return Some(item);
+            }
+            let (sub_path, node, exclude_metadata_children) = self.stack.pop()?;
+            for (l, child) in node.branches.iter() {
+                if exclude_metadata_children && l.is_metadata() {
+                    continue;
+                }
+                let mut child_path = sub_path.clone();
+                child_path.push(l);
+                self.stack.push((child_path, child, false));
+            }
+            for (v, _) in node.values.iter() {
+                self.buf.push_back((sub_path.clone(), v));
+            }
+        }
+    }
+}
+
+/// Lazy iterator behind [`Node::matches_filter`], analogous to [`MatchedIter`] but walking
+/// a wildcard *filter* against concrete stored topics instead of a concrete topic against
+/// stored filters.
+pub struct FilterMatchedIter<'a, V> {
+    node: &'a Node<V>,
+    path: &'a [Level],
+    sub_path: Option<Vec<&'a Level>>,
+    buf: std::collections::VecDeque<(Vec<&'a Level>, &'a V)>,
+    subtree: Option<SubtreeIter<'a, V>>,
+    sub_iters: Vec<Self>,
+}
+
+impl<'a, V> FilterMatchedIter<'a, V>
+where
+    V: Hash + Eq + Clone + Debug,
+{
+    #[inline]
+    fn new(node: &'a Node<V>, path: &'a [Level], sub_path: Vec<&'a Level>) -> Self {
+        Self { node, path, sub_path: Some(sub_path), buf: Default::default(), subtree: None, sub_iters: Vec::new() }
+    }
+
+    #[inline]
+    fn next_item(&mut self) -> Option<(Vec<&'a Level>, &'a V)> {
+        if let Some(item) = self.buf.pop_front() {
+            return Some(item);
+        }
+        if let Some(subtree) = self.subtree.as_mut() {
+            if let Some(item) = subtree.next() {
+                return Some(item);
+            }
+            self.subtree = None;
+        }
+        while !self.sub_iters.is_empty() {
+            if let Some(item) = self.sub_iters[0].next() {
+                return Some(item);
+            }
+            self.sub_iters.remove(0);
+        }
+        None
+    }
+
+    #[inline]
+    fn prepare(&mut self) {
+        let sub_path = self.sub_path.take().unwrap();
+        if self.path.is_empty() {
+            for (v, _) in self.node.values.iter() {
+                self.buf.push_back((sub_path.clone(), v));
+            }
+            return;
+        }
+
+        //A leading wildcard must not match topic names beginning with $.
+        let at_root = sub_path.is_empty();
+
+        match &self.path[0] {
+            Level::MultiWildcard => {
+                self.subtree = Some(SubtreeIter::new(self.node, sub_path, at_root));
+            }
+            Level::SingleWildcard => {
+                for (l, child) in self.node.branches.iter() {
+                    if at_root && l.is_metadata() {
+                        continue;
+                    }
+                    let mut child_path = sub_path.clone();
+                    child_path.push(l);
+                    self.sub_iters.push(FilterMatchedIter::new(child, &self.path[1..], child_path));
+                }
+            }
+            level => {
+                if let Some(child) = self.node.branches.get(level) {
+                    let mut child_path = sub_path;
+                    child_path.push(level);
+                    self.sub_iters.push(FilterMatchedIter::new(child, &self.path[1..], child_path));
+                }
+            }
+        }
+    }
+}
+
+impl<'a, V> Iterator for FilterMatchedIter<'a, V>
+where
+    V: Hash + Eq + Clone + Debug,
+{
+    type Item = (Topic, &'a V);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((levels, v)) = self.next_item() {
+            return Some((levels.to_topic(), v));
+        }
+        self.sub_path.as_ref()?;
+
+        self.prepare();
+
+        self.next_item().map(|(levels, v)| (levels.to_topic(), v))
+    }
+}
+
+/// A concurrent `TopicTree` for multi-core insert/match throughput: wraps N independent
+/// `Node` shards behind their own `RwLock`, each owning the topic filters whose first level
+/// hashes to it, so operations against top-level prefixes that hash to different shards
+/// (e.g. `iot/...` vs `a/...`) don't contend on the same lock. Topics with a leading `/`
+/// all have an empty first level and so all share one shard.
+///
+/// Filters that themselves begin with `+` or `#` can match topics with any first level, so
+/// they cannot be routed to a single shard; they are instead stored only in a dedicated
+/// "wildcard shard" that every `matches`/`matches_filter` call also consults.
+pub struct ShardedTopicTree<V> {
+    shards: Vec<RwLock<Node<V>>>,
+    wildcard_shard: RwLock<Node<V>>,
+}
+
+impl<V> ShardedTopicTree<V>
+where
+    V: Hash + Eq + Clone + Debug,
+{
+    /// Creates a tree with `shard_count` shards (clamped to at least 1).
+    pub fn new(shard_count: usize) -> Self {
+        let shard_count = shard_count.max(1);
+        Self {
+            shards: (0..shard_count).map(|_| RwLock::new(Node::default())).collect(),
+            wildcard_shard: RwLock::new(Node::default()),
+        }
+    }
+
+    //The level that actually determines placement: for a `$share/{group}/{filter}`
+    //subscription this is the real filter's first level, not "$share" itself.
+    fn route_level(levels: &[Level]) -> Option<&Level> {
+        if levels.first().map(|l| l.to_string().as_str() == SHARED_PREFIX).unwrap_or(false) {
+            levels.get(2)
+        } else {
+            levels.first()
+        }
+    }
+
+    #[inline]
+    fn shard_index(&self, level: &Level) -> usize {
+        let mut hasher = DefaultHasher::new();
+        level.to_string().hash(&mut hasher);
+        (hasher.finish() as usize) % self.shards.len()
+    }
+
+    //Routes to the wildcard shard when the routing level is itself a wildcard (or absent).
+    fn shard_for<'a>(&'a self, levels: &[Level]) -> &'a RwLock<Node<V>> {
+        match Self::route_level(levels) {
+            Some(Level::SingleWildcard) | Some(Level::MultiWildcard) | None => &self.wildcard_shard,
+            Some(level) => &self.shards[self.shard_index(level)],
+        }
+    }
+
+    pub fn insert(&self, topic_filter: &Topic, value: V) -> bool {
+        self.shard_for(topic_filter.levels()).write().unwrap().insert(topic_filter, value)
+    }
+
+    pub fn remove(&self, topic_filter: &Topic, value: &V) -> bool {
+        self.shard_for(topic_filter.levels()).write().unwrap().remove(topic_filter, value)
+    }
+
+    /// Fans out the match across every shard whose contents could contain a matching filter
+    /// (the wildcard shard is always consulted) and merges the per-shard results.
+    pub fn matches(&self, topic: &Topic) -> HashMap<Topic, Vec<V>> {
+        let mut out: HashMap<Topic, Vec<V>> = HashMap::default();
+        let mut merge = |shard: &RwLock<Node<V>>| {
+            for (topic, values) in shard.read().unwrap().old_matches(topic) {
+                out.entry(topic).or_default().extend(values);
+            }
+        };
+        if let Some(first) = topic.levels().first() {
+            merge(&self.shards[self.shard_index(first)]);
+        }
+        merge(&self.wildcard_shard);
+        out
+    }
+
+    /// Reverse lookup fanned out across shards. Because shard placement is keyed by
+    /// concrete topics' own first level, a concrete `filter` only ever needs the one shard
+    /// that owns it (plus the wildcard shard); a wildcard-rooted `filter` must be checked
+    /// against every shard. Values are cloned out from under each shard's read lock rather
+    /// than borrowed, since the lock guards cannot outlive this call.
+    pub fn matches_filter(&self, filter: &Topic) -> Vec<(Topic, V)> {
+        let mut out = Vec::new();
+        let mut scan = |shard: &RwLock<Node<V>>| {
+            let node = shard.read().unwrap();
+            out.extend(node.matches_filter(filter).map(|(t, v)| (t, v.clone())));
+        };
+        match Self::route_level(filter.levels()) {
+            Some(Level::SingleWildcard) | Some(Level::MultiWildcard) | None => {
+                for shard in &self.shards {
+                    scan(shard);
+                }
+            }
+            Some(level) => scan(&self.shards[self.shard_index(level)]),
+        }
+        scan(&self.wildcard_shard);
+        out
+    }
+
+    pub fn values_size(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().values_size()).sum::<usize>()
+            + self.wildcard_shard.read().unwrap().values_size()
+    }
+
+    pub fn nodes_size(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().nodes_size()).sum::<usize>()
+            + self.wildcard_shard.read().unwrap().nodes_size()
+    }
+}
+
+/// A single recorded mutation against a `Node`, as applied through
+/// [`Node::insert_logged`]/[`Node::remove_logged`].
+#[derive(Clone, Debug)]
+pub enum DeltaOp<V> {
+    Insert(Topic, V),
+    Remove(Topic, V),
+}
+
+impl<V> Serialize for DeltaOp<V>
+where
+    V: Serialize + Hash + Eq + Clone + Debug,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_tuple(3)?;
+        let (kind, topic, value) = match self {
+            DeltaOp::Insert(topic, value) => ("insert", topic, value),
+            DeltaOp::Remove(topic, value) => ("remove", topic, value),
+        };
+        s.serialize_element(kind)?;
+        s.serialize_element(topic.levels())?;
+        s.serialize_element(value)?;
+        s.end()
+    }
+}
+
+impl<'de, V> Deserialize<'de> for DeltaOp<V>
+where
+    V: DeserializeOwned + Hash + Eq + Clone + Debug,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct DeltaOpVisitor<V>(std::marker::PhantomData<V>);
+
+        impl<'de, V> Visitor<'de> for DeltaOpVisitor<V>
+        where
+            V: DeserializeOwned + Hash + Eq + Clone + Debug,
+        {
+            type Value = DeltaOp<V>;
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("struct DeltaOp")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                if seq.size_hint() != Some(3) {
+                    return Err(Error::invalid_type(serde::de::Unexpected::Seq, &self));
+                }
+
+                let kind = seq.next_element::<String>()?.ok_or_else(|| de::Error::missing_field("kind"))?;
+                let levels =
+                    seq.next_element::<Vec<Level>>()?.ok_or_else(|| de::Error::missing_field("topic"))?;
+                let value = seq.next_element::<V>()?.ok_or_else(|| de::Error::missing_field("value"))?;
+                let topic = Topic::from(levels);
+
+                match kind.as_str() {
+                    "insert" => Ok(DeltaOp::Insert(topic, value)),
+                    "remove" => Ok(DeltaOp::Remove(topic, value)),
+                    other => Err(de::Error::unknown_variant(other, &["insert", "remove"])),
+                }
+            }
+        }
+        deserializer.deserialize_tuple(3, DeltaOpVisitor(std::marker::PhantomData))
+    }
+}
+
+/// An ordered, appendable record of `DeltaOp`s, each tagged with a monotonically increasing
+/// sequence number. Lets a peer that has fallen behind catch up by transferring only the
+/// ops after the sequence number it last applied, instead of a full snapshot.
+pub struct DeltaLog<V> {
+    entries: Vec<(u64, DeltaOp<V>)>,
+    next_seq: u64,
+}
+
+impl<V> Default for DeltaLog<V> {
+    fn default() -> Self {
+        Self { entries: Vec::new(), next_seq: 0 }
+    }
+}
+
+impl<V> Serialize for DeltaLog<V>
+where
+    V: Serialize + Hash + Eq + Clone + Debug,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_tuple(2)?;
+        s.serialize_element(&self.next_seq)?;
+        s.serialize_element(&self.entries)?;
+        s.end()
+    }
+}
+
+impl<'de, V> Deserialize<'de> for DeltaLog<V>
+where
+    V: DeserializeOwned + Hash + Eq + Clone + Debug,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct DeltaLogVisitor<V>(std::marker::PhantomData<V>);
+
+        impl<'de, V> Visitor<'de> for DeltaLogVisitor<V>
+        where
+            V: DeserializeOwned + Hash + Eq + Clone + Debug,
+        {
+            type Value = DeltaLog<V>;
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("struct DeltaLog")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                if seq.size_hint() != Some(2) {
+                    return Err(Error::invalid_type(serde::de::Unexpected::Seq, &self));
+                }
+
+                let next_seq =
+                    seq.next_element::<u64>()?.ok_or_else(|| de::Error::missing_field("next_seq"))?;
+                let entries = seq
+                    .next_element::<Vec<(u64, DeltaOp<V>)>>()?
+                    .ok_or_else(|| de::Error::missing_field("entries"))?;
+
+                Ok(DeltaLog { entries, next_seq })
+            }
+        }
+        deserializer.deserialize_tuple(2, DeltaLogVisitor(std::marker::PhantomData))
+    }
+}
+
+impl<V> DeltaLog<V>
+where
+    V: Hash + Eq + Clone + Debug,
+{
+    fn push(&mut self, op: DeltaOp<V>) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.entries.push((seq, op));
+        seq
+    }
+
+    /// All ops with sequence number strictly greater than `seq`.
+    pub fn since(&self, seq: u64) -> impl Iterator<Item = &(u64, DeltaOp<V>)> {
+        self.entries.iter().filter(move |(s, _)| *s > seq)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Deduplicates repeated ops against the same `(Topic, V)` down to just the last one, so
+/// `log` shrinks to the minimal set of ops needed to bring a peer's tree to the same state.
+/// Order is preserved via a `LinkedHashMap` keyed by `(Topic, V)` so replay still applies
+/// ops in a sane sequence.
+///
+/// Earlier attempts folded a matching `Insert`+`Remove` pair to nothing, but that's unsound:
+/// a peer that caught up at any seq between the insert and the remove already applied the
+/// insert, and with both gone it would never learn the value was removed, leaving it stuck
+/// with stale state forever. So an `Insert` superseded by a later `Remove` (or vice versa)
+/// is never cancelled - only the earlier, now-redundant op for that key is dropped, and the
+/// later, still-authoritative one survives under its own seq. Replaying a lone `Remove` of
+/// something a peer never inserted is a harmless no-op, so this is safe for every peer
+/// regardless of how far behind it is.
+///
+/// Sequence numbers are left exactly as they were (neither renumbered nor is `next_seq`
+/// touched): a surviving op keeps the seq it was originally pushed with. That's what makes
+/// [`DeltaLog::since`] still work correctly after compaction - a peer that has already
+/// applied everything up to some seq N can compact the log in place and still ask
+/// `since(N)` for whatever is left.
+pub fn compact<V>(log: &mut DeltaLog<V>)
+where
+    V: Hash + Eq + Clone + Debug,
+{
+    let mut folded: LinkedHashMap<(Topic, V), (u64, DeltaOp<V>)> = LinkedHashMap::default();
+    for (seq, op) in log.entries.drain(..) {
+        let key = match &op {
+            DeltaOp::Insert(t, v) | DeltaOp::Remove(t, v) => (t.clone(), v.clone()),
+        };
+        //Last write for a given (Topic, V) always wins, whether it's an Insert or a Remove.
+        folded.insert(key, (seq, op));
+    }
+    log.entries = folded.into_iter().map(|(_, entry)| entry).collect();
+}
+
+impl<V> Node<V>
+where
+    V: Hash + Eq + Clone + Debug,
+{
+    /// Like [`insert`](Self::insert), but also records the mutation in `log` so it can later
+    /// be shipped to a peer via [`apply_delta`](Self::apply_delta).
+    pub fn insert_logged(&mut self, topic_filter: &Topic, value: V, log: &mut DeltaLog<V>) -> bool {
+        let inserted = self.insert(topic_filter, value.clone());
+        if inserted {
+            log.push(DeltaOp::Insert(topic_filter.clone(), value));
+        }
+        inserted
+    }
+
+    /// Like [`remove`](Self::remove), but also records the mutation in `log`.
+    pub fn remove_logged(&mut self, topic_filter: &Topic, value: &V, log: &mut DeltaLog<V>) -> bool {
+        let removed = self.remove(topic_filter, value);
+        if removed {
+            log.push(DeltaOp::Remove(topic_filter.clone(), value.clone()));
+        }
+        removed
+    }
+
+    /// Replays a (possibly compacted) delta log onto this tree, bringing it up to date with
+    /// whatever peer produced the log.
+    pub fn apply_delta(&mut self, log: &DeltaLog<V>) {
+        for (_, op) in &log.entries {
+            match op {
+                DeltaOp::Insert(topic, value) => {
+                    self.insert(topic, value.clone());
+                }
+                DeltaOp::Remove(topic, value) => {
+                    self.remove(topic, value);
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::NodeId;
-    use super::{Topic, TopicTree, VecToString};
+    use super::{compact, DeltaLog, DeltaOp, ShardedTopicTree, Topic, TopicTree, VecToString};
     use std::str::FromStr;
 
     fn match_one(topics: &TopicTree<NodeId>, topic: &str, vs: &[NodeId]) -> bool {
@@ -536,4 +1371,134 @@ mod tests {
         assert!(topics.is_match(&t));
         println!("is_matches cost time: {:?}", start.elapsed());
     }
+
+    #[test]
+    fn matches_filter_reverse() {
+        let mut topics: TopicTree<NodeId> = TopicTree::default();
+        topics.insert(&Topic::from_str("iot/a/temp").unwrap(), 1);
+        topics.insert(&Topic::from_str("iot/b/temp").unwrap(), 2);
+        topics.insert(&Topic::from_str("iot/b/humidity").unwrap(), 3);
+        topics.insert(&Topic::from_str("other/x").unwrap(), 4);
+
+        let collect = |filter: &str| -> Vec<NodeId> {
+            let f = Topic::from_str(filter).unwrap();
+            let mut vs: Vec<NodeId> = topics.matches_filter(&f).map(|(_, v)| *v).collect();
+            vs.sort_unstable();
+            vs
+        };
+
+        //Concrete filter
+        assert_eq!(collect("iot/a/temp"), vec![1]);
+
+        //Single-level wildcard
+        assert_eq!(collect("iot/+/temp"), vec![1, 2]);
+
+        //Multi-level wildcard
+        assert_eq!(collect("iot/#"), vec![1, 2, 3]);
+        assert_eq!(collect("#"), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn matches_filter_dollar_exclusion() {
+        let mut topics: TopicTree<NodeId> = TopicTree::default();
+        topics.insert(&Topic::from_str("$SYS/broker/clients").unwrap(), 1);
+        topics.insert(&Topic::from_str("sensors/temp").unwrap(), 2);
+
+        let collect = |filter: &str| -> Vec<NodeId> {
+            let f = Topic::from_str(filter).unwrap();
+            let mut vs: Vec<NodeId> = topics.matches_filter(&f).map(|(_, v)| *v).collect();
+            vs.sort_unstable();
+            vs
+        };
+
+        //A leading wildcard must not match topic names beginning with $.
+        assert_eq!(collect("#"), vec![2]);
+        assert_eq!(collect("+/broker/clients"), Vec::<NodeId>::new());
+
+        //But an explicit, non-wildcard $ level still matches normally.
+        assert_eq!(collect("$SYS/broker/clients"), vec![1]);
+        assert_eq!(collect("$SYS/#"), vec![1]);
+    }
+
+    #[test]
+    fn sharded_matches_filter_reverse() {
+        let tree: ShardedTopicTree<NodeId> = ShardedTopicTree::new(4);
+        tree.insert(&Topic::from_str("iot/a/temp").unwrap(), 1);
+        tree.insert(&Topic::from_str("iot/b/temp").unwrap(), 2);
+        tree.insert(&Topic::from_str("weather/x").unwrap(), 3);
+
+        let collect = |filter: &str| -> Vec<NodeId> {
+            let f = Topic::from_str(filter).unwrap();
+            let mut vs: Vec<NodeId> = tree.matches_filter(&f).into_iter().map(|(_, v)| v).collect();
+            vs.sort_unstable();
+            vs
+        };
+
+        //Concrete filter: routed to the single shard owning "iot".
+        assert_eq!(collect("iot/a/temp"), vec![1]);
+
+        //Wildcard within a known first level: still just that shard.
+        assert_eq!(collect("iot/+/temp"), vec![1, 2]);
+
+        //Wildcard-rooted filter: must fan out and merge across every shard.
+        assert_eq!(collect("#"), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn cbor_roundtrip() {
+        let mut topics: TopicTree<NodeId> = TopicTree::default();
+        topics.insert(&Topic::from_str("/a/b/c").unwrap(), 1);
+        topics.insert(&Topic::from_str("/a/+").unwrap(), 2);
+        topics.insert(&Topic::from_str("/iot/#").unwrap(), 3);
+
+        let topics = TopicTree::<NodeId>::from_cbor(&topics.snapshot_cbor()).unwrap();
+
+        assert!(match_one(&topics, "/a/b/c", &[1]));
+        assert!(match_one(&topics, "/a/b", &[2]));
+        assert!(match_one(&topics, "/iot/x/y", &[3]));
+    }
+
+    #[test]
+    fn cbor_roundtrip_preserves_shared_groups() {
+        let mut topics: TopicTree<NodeId> = TopicTree::default();
+        topics.insert(&Topic::from_str("$share/g1/iot/+/temp").unwrap(), 1);
+        topics.insert(&Topic::from_str("$share/g1/iot/+/temp").unwrap(), 2);
+
+        let topics = TopicTree::<NodeId>::from_cbor(&topics.snapshot_cbor()).unwrap();
+
+        let t = Topic::from_str("iot/a/temp").unwrap();
+        let matched = topics.matches_shared(&t, super::SelectStrategy::RoundRobin);
+        let members: Vec<NodeId> = matched.values().flatten().copied().collect();
+        assert_eq!(members.len(), 1);
+        assert!(members[0] == 1 || members[0] == 2);
+    }
+
+    #[test]
+    fn compact_preserves_since_contract() {
+        let mut topics: TopicTree<NodeId> = TopicTree::default();
+        let mut log: DeltaLog<NodeId> = DeltaLog::default();
+
+        topics.insert_logged(&Topic::from_str("a/b").unwrap(), 1, &mut log); //seq 0
+        topics.insert_logged(&Topic::from_str("a/c").unwrap(), 2, &mut log); //seq 1
+        topics.remove_logged(&Topic::from_str("a/b").unwrap(), &1, &mut log); //seq 2
+
+        //A peer caught up through seq 0 has already applied the seq-0 insert of (a/b, 1).
+        let caught_up_through = 0u64;
+        let mut expected: Vec<u64> = log.since(caught_up_through).map(|(seq, _)| *seq).collect();
+        expected.sort_unstable();
+
+        compact(&mut log);
+
+        //The seq-0 insert is now redundant (superseded by the seq-2 remove) and is dropped,
+        //but the seq-2 remove itself must survive: the peer above already has (a/b, 1) and
+        //would otherwise never learn it was removed. Order may change, so compare as sets.
+        let mut after: Vec<u64> = log.since(caught_up_through).map(|(seq, _)| *seq).collect();
+        after.sort_unstable();
+        assert_eq!(after, expected);
+        assert_eq!(after, vec![1, 2]);
+
+        assert!(log.since(caught_up_through).any(|(seq, op)| {
+            matches!(op, DeltaOp::Remove(t, v) if *seq == 2 && t.to_string() == "a/b" && *v == 1)
+        }));
+    }
 }